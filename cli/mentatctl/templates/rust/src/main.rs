@@ -1,25 +1,33 @@
-use std::io::{self, Read, Write};
-use std::time::Instant;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-use serde_json;
 
 /// Input data structure
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Default)]
 struct InputData {
     text: Option<String>,
 }
 
 /// Metadata structure for MentatLab metrics
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct MentatMeta {
     tokens_input: Option<usize>,
     tokens_output: Option<usize>,
     seconds: Option<f64>,
     model: String,
+    /// Set when a wall-clock timeout was configured, to whether it fired.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timed_out: Option<bool>,
+    /// Description of the execution guards applied, if sandboxing was on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sandbox: Option<String>,
 }
 
 /// Output data structure
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct OutputData {
     result: String,
     mentat_meta: MentatMeta,
@@ -32,6 +40,36 @@ struct ErrorResponse {
     mentat_meta: MentatMeta,
 }
 
+/// A JSON-RPC 2.0 request envelope carrying one `InputData` call.
+#[derive(Deserialize)]
+struct JsonRpcRequest {
+    #[allow(dead_code)]
+    jsonrpc: String,
+    method: String,
+    /// Defaults to empty so body-less calls like `"ping"` don't need one.
+    #[serde(default)]
+    params: InputData,
+    id: serde_json::Value,
+}
+
+/// An error message like `ErrorResponse.error`, paired with a JSON-RPC code.
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// A JSON-RPC 2.0 response envelope; exactly one of `result`/`error` is set.
+#[derive(Serialize)]
+struct JsonRpcResponse {
+    jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<OutputData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: serde_json::Value,
+}
+
 /// Process the agent request
 fn process_request(input_data: InputData) -> OutputData {
     let start_time = Instant::now();
@@ -54,10 +92,67 @@ fn process_request(input_data: InputData) -> OutputData {
             tokens_output: if result.is_empty() { Some(0) } else { Some(result.split_whitespace().count()) },
             seconds: Some((processing_time * 1000.0).round() / 1000.0),
             model: "{{AGENT_ID}}".to_string(),
+            timed_out: None,
+            sandbox: None,
         },
     }
 }
 
+/// One streamed event, internally tagged by `"type"` so a consumer can tell
+/// partial text from the terminal result without buffering the whole line.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum AgentEvent {
+    #[serde(rename = "chunk")]
+    Chunk { delta: String },
+    #[serde(rename = "result")]
+    Result(OutputData),
+    #[serde(rename = "error")]
+    Error(ErrorResponse),
+}
+
+/// Process the agent request, emitting incremental progress through `emit`
+/// before the terminal `AgentEvent::Result`.
+///
+/// Mirrors `process_request`'s placeholder logic one word at a time; replace
+/// alongside it when implementing real streaming generation. `tokens_output`
+/// on the final event reflects the summed emitted chunks.
+fn process_request_streaming<F: FnMut(AgentEvent)>(input_data: InputData, mut emit: F) -> OutputData {
+    let start_time = Instant::now();
+
+    // Extract input text
+    let input_text = input_data.text.unwrap_or_default();
+
+    // TODO: Implement your agent logic here, emitting partial results via
+    // `emit` as they become available rather than building the whole
+    // string up front.
+    let mut result = String::new();
+    let mut tokens_output = 0usize;
+    for word in format!("Processed: {}", input_text).split_whitespace() {
+        let delta = if result.is_empty() { word.to_string() } else { format!(" {}", word) };
+        result.push_str(&delta);
+        tokens_output += 1;
+        emit(AgentEvent::Chunk { delta });
+    }
+
+    // Calculate processing time
+    let processing_time = start_time.elapsed().as_secs_f64();
+
+    let output_data = OutputData {
+        result,
+        mentat_meta: MentatMeta {
+            tokens_input: if input_text.is_empty() { Some(0) } else { Some(input_text.split_whitespace().count()) },
+            tokens_output: Some(tokens_output),
+            seconds: Some((processing_time * 1000.0).round() / 1000.0),
+            model: "{{AGENT_ID}}".to_string(),
+            timed_out: None,
+            sandbox: guard_report().sandbox.clone(),
+        },
+    };
+    emit(AgentEvent::Result(output_data.clone()));
+    output_data
+}
+
 /// Create error response
 fn create_error_response(error_msg: String) -> ErrorResponse {
     ErrorResponse {
@@ -67,58 +162,878 @@ fn create_error_response(error_msg: String) -> ErrorResponse {
             tokens_output: None,
             seconds: None,
             model: "{{AGENT_ID}}".to_string(),
+            timed_out: None,
+            sandbox: guard_report().sandbox.clone(),
         },
     }
 }
 
-fn main() -> io::Result<()> {
-    // Read JSON input from stdin
+/// Best-effort Linux process hardening: `prctl(PR_SET_NO_NEW_PRIVS)`, a
+/// seccomp-bpf syscall filter, and an address-space `setrlimit` ceiling.
+/// Declared via raw FFI rather than a `libc`/`seccomp` dependency since this
+/// template has no crate manifest of its own.
+#[cfg(target_os = "linux")]
+mod linux_sandbox {
+    #[repr(C)]
+    struct RLimit {
+        cur: u64,
+        max: u64,
+    }
+
+    /// One BPF instruction, matching the kernel's `struct sock_filter`.
+    #[repr(C)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    /// A BPF program, matching the kernel's `struct sock_fprog`.
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    const RLIMIT_AS: i32 = 9;
+    const PR_SET_NO_NEW_PRIVS: i32 = 38;
+    const PR_SET_SECCOMP: i32 = 22;
+    const SECCOMP_MODE_FILTER: u64 = 2;
+
+    const BPF_LD_W_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+    const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+    const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+
+    const AUDIT_ARCH_X86_64: u32 = 0xC000_003E;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO_EPERM: u32 = 0x0005_0000 | 1;
+
+    // Offsets into the kernel's `struct seccomp_data`.
+    const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+    const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+
+    // x86_64 syscall numbers for the filesystem/network primitives denied
+    // below. This filter is x86_64-only; `apply_seccomp_filter` is
+    // `#[cfg(target_arch = "x86_64")]`-gated so other architectures fall
+    // back to `apply_no_new_privs` only, rather than installing a BPF
+    // program whose arch-check branch denies every syscall.
+    const SYS_OPEN: u32 = 2;
+    const SYS_OPENAT: u32 = 257;
+    const SYS_SOCKET: u32 = 41;
+    const SYS_CONNECT: u32 = 42;
+
+    unsafe extern "C" {
+        fn setrlimit(resource: i32, rlim: *const RLimit) -> i32;
+        fn prctl(option: i32, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> i32;
+    }
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter { code, jt: 0, jf: 0, k }
+    }
+
+    /// `if syscall_nr == k { return EPERM }`, as two BPF instructions.
+    fn deny_if_eq(k: u32) -> [SockFilter; 2] {
+        [
+            SockFilter { code: BPF_JMP_JEQ_K, jt: 0, jf: 1, k },
+            stmt(BPF_RET_K, SECCOMP_RET_ERRNO_EPERM),
+        ]
+    }
+
+    /// Block the process (and anything it execs) from gaining privileges it
+    /// doesn't already have.
+    pub fn apply_no_new_privs() -> bool {
+        unsafe { prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0 }
+    }
+
+    /// Install a syscall allowlist-by-default filter that denies
+    /// `open`/`openat`/`socket`/`connect` — the filesystem and network
+    /// primitives a compromised or buggy `process_request` would otherwise
+    /// reach for — and returns EPERM for them instead of terminating the
+    /// process. Returns whether the filter was actually installed; callers
+    /// must not report syscall confinement when this returns `false`
+    /// (wrong architecture, no `CAP_SYS_ADMIN`/`no_new_privs`, or a kernel
+    /// without `CONFIG_SECCOMP_FILTER`).
+    ///
+    /// The BPF program's syscall numbers are x86_64-specific, so this is
+    /// `#[cfg(target_arch = "x86_64")]`-gated rather than relying on the
+    /// program's own arch-check instruction: that instruction denies every
+    /// syscall on a mismatched architecture, which would mean `prctl`
+    /// reports success while the very next `read`/`write` the process makes
+    /// gets `EPERM`'d. Better to not install at all on those hosts.
+    #[cfg(target_arch = "x86_64")]
+    pub fn apply_seccomp_filter() -> bool {
+        let mut filter = vec![
+            stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH_OFFSET),
+            SockFilter { code: BPF_JMP_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH_X86_64 },
+            stmt(BPF_RET_K, SECCOMP_RET_ERRNO_EPERM),
+            stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR_OFFSET),
+        ];
+        for syscall_nr in [SYS_OPEN, SYS_OPENAT, SYS_SOCKET, SYS_CONNECT] {
+            filter.extend(deny_if_eq(syscall_nr));
+        }
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+        let prog = SockFprog { len: filter.len() as u16, filter: filter.as_ptr() };
+        unsafe { prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog as u64, 0, 0) == 0 }
+    }
+
+    /// Non-x86_64 Linux hosts (aarch64, etc.): the BPF program above can't be
+    /// installed safely, so fall back to `apply_no_new_privs` only rather
+    /// than bricking the process. See the arch-gated `apply_seccomp_filter`
+    /// above for why.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn apply_seccomp_filter() -> bool {
+        false
+    }
+
+    /// Cap the process's virtual address space to `bytes`, best-effort.
+    pub fn apply_memory_ceiling(bytes: u64) -> bool {
+        let limit = RLimit { cur: bytes, max: bytes };
+        unsafe { setrlimit(RLIMIT_AS, &limit) == 0 }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod linux_sandbox {
+    pub fn apply_no_new_privs() -> bool {
+        false
+    }
+
+    pub fn apply_seccomp_filter() -> bool {
+        false
+    }
+
+    pub fn apply_memory_ceiling(_bytes: u64) -> bool {
+        false
+    }
+}
+
+/// Whether execution guards (sandboxing, resource limits) are enabled, per
+/// `MENTAT_SANDBOX=1`. Opt-in so existing deployments aren't surprised by a
+/// stripped environment or a killed process.
+fn sandbox_requested() -> bool {
+    std::env::var("MENTAT_SANDBOX").map(|v| v == "1").unwrap_or(false)
+}
+
+/// Clear the process environment down to an explicit allowlist
+/// (`MENTAT_ALLOWED_ENV`, a comma-separated list of names) plus the
+/// `MENTAT_*` variables this template itself reads, so a compromised
+/// `process_request` can't read secrets out of the parent's environment.
+///
+/// # Safety
+/// `std::env::remove_var` is unsound if other threads are reading/writing
+/// the environment concurrently. This must run before `process_request` (and
+/// hence before `process_with_timeout` spawns its worker thread) — it does,
+/// since `apply_execution_guards` runs once at startup, before any request
+/// is handled.
+fn strip_environment() {
+    let allowed: std::collections::HashSet<String> = std::env::var("MENTAT_ALLOWED_ENV")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    for (key, _) in std::env::vars() {
+        if key.starts_with("MENTAT_") || allowed.contains(&key) {
+            continue;
+        }
+        // SAFETY: called once from `apply_execution_guards` at process
+        // startup, before any worker thread exists.
+        unsafe {
+            std::env::remove_var(key);
+        }
+    }
+}
+
+/// What execution guards are actually in effect, surfaced in every
+/// `MentatMeta.sandbox` so the orchestrator can see that isolation was applied
+/// rather than silently assuming it.
+struct GuardReport {
+    sandbox: Option<String>,
+}
+
+/// Apply the configured execution guards once at process startup: env
+/// stripping, `no_new_privs`, the seccomp filesystem/network denylist, and an
+/// optional `MENTAT_MEMORY_LIMIT_MB`
+/// address-space ceiling. A no-op unless `MENTAT_SANDBOX=1`.
+fn apply_execution_guards() -> GuardReport {
+    if !sandbox_requested() {
+        return GuardReport { sandbox: None };
+    }
+
+    strip_environment();
+    let mut applied = vec!["env-stripped"];
+
+    // `PR_SET_SECCOMP` requires either CAP_SYS_ADMIN or no_new_privs, so the
+    // filter is only attempted once no_new_privs is confirmed on.
+    let no_new_privs = linux_sandbox::apply_no_new_privs();
+    if no_new_privs {
+        applied.push("no-new-privs");
+        if linux_sandbox::apply_seccomp_filter() {
+            applied.push("seccomp-fs-net-denylist");
+        }
+    }
+
+    if let Some(limit_mb) = std::env::var("MENTAT_MEMORY_LIMIT_MB")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        if linux_sandbox::apply_memory_ceiling(limit_mb * 1024 * 1024) {
+            applied.push("memory-ceiling");
+        }
+    }
+
+    // Report exactly what was applied — never imply syscall confinement
+    // that didn't actually install, so the orchestrator can trust this
+    // field when deciding whether to run untrusted agent code.
+    GuardReport { sandbox: Some(applied.join("+")) }
+}
+
+static GUARD_REPORT: OnceLock<GuardReport> = OnceLock::new();
+
+/// Install execution guards; must be called once before any request is
+/// processed. Safe to call more than once, only the first call takes effect.
+fn init_execution_guards() {
+    let _ = GUARD_REPORT.get_or_init(apply_execution_guards);
+}
+
+fn guard_report() -> &'static GuardReport {
+    GUARD_REPORT.get_or_init(|| GuardReport { sandbox: None })
+}
+
+/// Wall-clock timeout for `process_request`, from `MENTAT_TIMEOUT_MS` (no
+/// limit if unset or unparsable).
+fn configured_timeout() -> Option<Duration> {
+    std::env::var("MENTAT_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis)
+}
+
+/// Run `process_request` on a worker thread so an enforced `timeout` can be
+/// observed from the caller's side without `process_request` itself needing
+/// to cooperate. On overrun the worker thread is left to finish in the
+/// background and its result discarded; std gives no safe way to cancel a
+/// running thread, so this is a best-effort guard, not a hard kill.
+fn process_with_timeout(input_data: InputData, timeout: Option<Duration>) -> Result<OutputData, String> {
+    let Some(timeout) = timeout else {
+        return Ok(process_request(input_data));
+    };
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(process_request(input_data));
+    });
+
+    rx.recv_timeout(timeout)
+        .map_err(|_| format!("process_request exceeded timeout of {}ms", timeout.as_millis()))
+}
+
+/// Run `process_request` behind the configured execution guards, tagging the
+/// result with whether a timeout was enforced and which sandbox guards were
+/// active. This is what every non-streaming transport (one-shot, loop, RPC,
+/// framed) should call instead of `process_request` directly; streaming uses
+/// the analogous `guarded_process_streaming`.
+#[allow(clippy::result_large_err)]
+fn guarded_process(input_data: InputData) -> Result<OutputData, ErrorResponse> {
+    let timeout = configured_timeout();
+    let guard = guard_report();
+
+    match process_with_timeout(input_data, timeout) {
+        Ok(mut output) => {
+            output.mentat_meta.sandbox = guard.sandbox.clone();
+            if timeout.is_some() {
+                output.mentat_meta.timed_out = Some(false);
+            }
+            Ok(output)
+        }
+        Err(message) => {
+            let mut error_response = create_error_response(message);
+            error_response.mentat_meta.sandbox = guard.sandbox.clone();
+            error_response.mentat_meta.timed_out = Some(true);
+            Err(error_response)
+        }
+    }
+}
+
+/// Build the `ErrorResponse` emitted when the streaming guard's timeout
+/// fires, tagged the same way `process_with_timeout`'s failure is.
+fn streaming_timeout_error(timeout: Duration) -> ErrorResponse {
+    let mut error_response = create_error_response(format!(
+        "process_request_streaming exceeded timeout of {}ms",
+        timeout.as_millis()
+    ));
+    error_response.mentat_meta.sandbox = guard_report().sandbox.clone();
+    error_response.mentat_meta.timed_out = Some(true);
+    error_response
+}
+
+/// Run `process_request_streaming` behind the same `MENTAT_TIMEOUT_MS`
+/// guard as `guarded_process`, forwarding each `AgentEvent` to `emit` as
+/// it's produced. When a timeout is configured, the agent runs on a worker
+/// thread; on overrun, `emit` receives a timeout `AgentEvent::Error` instead
+/// of waiting indefinitely, and the worker is left to finish in the
+/// background with its remaining events discarded — the same best-effort
+/// contract as `process_with_timeout`. The terminal `Result` event's
+/// `timed_out` is set to `Some(false)` whenever a timeout was configured but
+/// didn't fire.
+fn guarded_process_streaming<F: FnMut(AgentEvent)>(input_data: InputData, mut emit: F) {
+    let Some(timeout) = configured_timeout() else {
+        process_request_streaming(input_data, emit);
+        return;
+    };
+
+    let (tx, rx) = mpsc::channel::<AgentEvent>();
+    thread::spawn(move || {
+        process_request_streaming(input_data, |event| {
+            let _ = tx.send(event);
+        });
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) => remaining,
+            None => {
+                emit(AgentEvent::Error(streaming_timeout_error(timeout)));
+                return;
+            }
+        };
+        match rx.recv_timeout(remaining) {
+            Ok(AgentEvent::Result(mut output)) => {
+                output.mentat_meta.timed_out = Some(false);
+                emit(AgentEvent::Result(output));
+                return;
+            }
+            Ok(event) => emit(event),
+            Err(_) => {
+                emit(AgentEvent::Error(streaming_timeout_error(timeout)));
+                return;
+            }
+        }
+    }
+}
+
+/// One framed read: either a decoded message body, or a recoverable framing
+/// error to report without tearing down the stream.
+enum FramedRead {
+    Message(String),
+    Error(String),
+}
+
+/// Read one Content-Length-framed message from `reader`: ASCII headers
+/// terminated by a blank line, then exactly `Content-Length` body bytes.
+///
+/// Returns `Ok(None)` at a clean EOF before any header line is read. A
+/// header block with no usable `Content-Length`, or a body that hits EOF
+/// before `Content-Length` bytes are available, resyncs by reporting the
+/// error and letting the next call resume scanning for a header line from
+/// the current stream position, rather than aborting. Only a genuine I/O
+/// error (not EOF) propagates as `Err`.
+fn read_framed_message<R: BufRead>(reader: &mut R) -> io::Result<Option<FramedRead>> {
+    let mut content_length: Option<usize> = None;
+    let mut saw_any_header_line = false;
+
+    loop {
+        let mut line = String::new();
+        let bytes_read = match reader.read_line(&mut line) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Ok(Some(FramedRead::Error("Header line is not valid UTF-8".to_string())));
+            }
+            Err(e) => return Err(e),
+        };
+        if bytes_read == 0 {
+            return if saw_any_header_line {
+                Ok(Some(FramedRead::Error("Unexpected EOF while reading headers".to_string())))
+            } else {
+                Ok(None)
+            };
+        }
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if trimmed.is_empty() {
+            break;
+        }
+        saw_any_header_line = true;
+        if let Some((key, value)) = trimmed.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let content_length = match content_length {
+        Some(n) => n,
+        None => {
+            return Ok(Some(FramedRead::Error(
+                "Malformed header block: missing Content-Length".to_string(),
+            )));
+        }
+    };
+
+    let mut body = vec![0u8; content_length];
+    if let Err(e) = reader.read_exact(&mut body) {
+        return if e.kind() == io::ErrorKind::UnexpectedEof {
+            Ok(Some(FramedRead::Error(format!(
+                "Unexpected EOF before {} header-declared Content-Length bytes were read",
+                content_length
+            ))))
+        } else {
+            Err(e)
+        };
+    }
+    let body = match String::from_utf8(body) {
+        Ok(body) => body,
+        Err(e) => return Ok(Some(FramedRead::Error(format!("Body is not valid UTF-8: {}", e)))),
+    };
+    Ok(Some(FramedRead::Message(body)))
+}
+
+/// Write one Content-Length-framed message: the header block, then `body`
+/// verbatim, with a flush so a streaming consumer sees it immediately.
+fn write_framed_message<W: Write>(mut out: W, body: &str) -> io::Result<()> {
+    write!(out, "Content-Length: {}\r\n\r\n", body.len())?;
+    out.write_all(body.as_bytes())?;
+    out.flush()
+}
+
+/// Whether the process should speak Content-Length framed messages, per
+/// `--framed` or `MENTAT_FRAMED=1`.
+fn framed_mode_requested() -> bool {
+    std::env::args().any(|arg| arg == "--framed")
+        || std::env::var("MENTAT_FRAMED").map(|v| v == "1").unwrap_or(false)
+}
+
+/// Run the agent over the Content-Length framed protocol: one frame in, one
+/// frame out, until EOF. Binary-safe for payloads containing embedded
+/// newlines, unlike the line-oriented loop mode.
+fn run_framed_loop() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    loop {
+        match read_framed_message(&mut reader)? {
+            None => break,
+            Some(FramedRead::Error(msg)) => {
+                eprintln!("Framing error: {}", msg);
+                let error_response = create_error_response(msg);
+                let body = serde_json::to_string(&error_response).unwrap();
+                write_framed_message(&mut stdout, &body)?;
+            }
+            Some(FramedRead::Message(body)) => {
+                let body = match serde_json::from_str::<InputData>(body.trim()) {
+                    Ok(input_data) => match guarded_process(input_data) {
+                        Ok(output_data) => serde_json::to_string(&output_data).unwrap(),
+                        Err(error_response) => serde_json::to_string(&error_response).unwrap(),
+                    },
+                    Err(e) => {
+                        eprintln!("JSON parse error: {}", e);
+                        let error_response = create_error_response(format!("Invalid JSON input: {}", e));
+                        serde_json::to_string(&error_response).unwrap()
+                    }
+                };
+                write_framed_message(&mut stdout, &body)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the process should emit incremental `AgentEvent`s instead of one
+/// terminal `OutputData`, per `--stream` or `MENTAT_STREAM=1`.
+fn streaming_mode_requested() -> bool {
+    std::env::args().any(|arg| arg == "--stream")
+        || std::env::var("MENTAT_STREAM").map(|v| v == "1").unwrap_or(false)
+}
+
+/// Write one `AgentEvent` as a JSON line, flushing so a consumer sees it as
+/// soon as it's produced.
+fn emit_event<W: Write>(out: &mut W, event: AgentEvent) -> io::Result<()> {
+    writeln!(out, "{}", serde_json::to_string(&event).unwrap())?;
+    out.flush()
+}
+
+/// Run the agent in streaming mode: read one `InputData` from stdin, write
+/// newline-delimited `AgentEvent`s to stdout as `guarded_process_streaming`
+/// produces them.
+fn run_streaming() -> io::Result<()> {
     let mut input_buffer = String::new();
     io::stdin().read_to_string(&mut input_buffer)?;
-    
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
     if input_buffer.trim().is_empty() {
         let error_response = create_error_response("No input received from stdin".to_string());
-        let json_output = serde_json::to_string(&error_response).unwrap();
-        print!("{}", json_output);
-        io::stdout().flush()?;
+        emit_event(&mut stdout, AgentEvent::Error(error_response))?;
         std::process::exit(1);
     }
-    
-    // Parse JSON input
-    let input_data: InputData = match serde_json::from_str(&input_buffer.trim()) {
+
+    let input_data: InputData = match serde_json::from_str(input_buffer.trim()) {
+        Ok(data) => data,
+        Err(e) => {
+            let error_response = create_error_response(format!("Invalid JSON input: {}", e));
+            emit_event(&mut stdout, AgentEvent::Error(error_response))?;
+            eprintln!("JSON parse error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    guarded_process_streaming(input_data, |event| {
+        let _ = emit_event(&mut stdout, event);
+    });
+
+    Ok(())
+}
+
+/// Handle a single request body, writing exactly one JSON line to stdout.
+///
+/// Returns `Err` only when the line itself could not be written (i.e. an I/O
+/// failure), never for a parse or processing error — those are reported as an
+/// `ErrorResponse` on stdout so the caller (one-shot or loop mode) can decide
+/// whether to keep going.
+fn handle_request_line(line: &str) -> io::Result<bool> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    let input_data: InputData = match serde_json::from_str(line) {
         Ok(data) => {
             eprintln!("Processing input: {}", serde_json::to_string(&data).unwrap_or_default());
             data
         }
         Err(e) => {
             let error_response = create_error_response(format!("Invalid JSON input: {}", e));
-            let json_output = serde_json::to_string(&error_response).unwrap();
-            print!("{}", json_output);
-            io::stdout().flush()?;
+            writeln!(stdout, "{}", serde_json::to_string(&error_response).unwrap())?;
+            stdout.flush()?;
             eprintln!("JSON parse error: {}", e);
-            std::process::exit(1);
+            return Ok(false);
         }
     };
-    
-    // Process the request
-    let output_data = process_request(input_data);
-    
-    // Write JSON output to stdout
+
+    let output_data = match guarded_process(input_data) {
+        Ok(output_data) => output_data,
+        Err(error_response) => {
+            writeln!(stdout, "{}", serde_json::to_string(&error_response).unwrap())?;
+            stdout.flush()?;
+            eprintln!("process_request guard rejected the request: {}", error_response.error);
+            return Ok(false);
+        }
+    };
+
     match serde_json::to_string(&output_data) {
         Ok(json_output) => {
-            print!("{}", json_output);
-            io::stdout().flush()?;
+            writeln!(stdout, "{}", json_output)?;
+            stdout.flush()?;
             eprintln!("Processing completed successfully");
+            Ok(true)
         }
         Err(e) => {
             let error_response = create_error_response(format!("JSON serialization error: {}", e));
-            let json_output = serde_json::to_string(&error_response).unwrap();
-            print!("{}", json_output);
-            io::stdout().flush()?;
+            writeln!(stdout, "{}", serde_json::to_string(&error_response).unwrap())?;
+            stdout.flush()?;
             eprintln!("JSON serialization error: {}", e);
-            std::process::exit(1);
+            Ok(false)
         }
     }
-    
+}
+
+/// Whether the process should run as a long-lived agent loop instead of
+/// exiting after one request, per `--loop` or `MENTAT_LOOP=1`.
+fn loop_mode_requested() -> bool {
+    std::env::args().any(|arg| arg == "--loop")
+        || std::env::var("MENTAT_LOOP").map(|v| v == "1").unwrap_or(false)
+}
+
+/// Whether the process should speak the JSON-RPC 2.0 envelope, per `--rpc`
+/// or `MENTAT_RPC=1`.
+fn rpc_mode_requested() -> bool {
+    std::env::args().any(|arg| arg == "--rpc")
+        || std::env::var("MENTAT_RPC").map(|v| v == "1").unwrap_or(false)
+}
+
+/// Health info returned for the `"ping"` RPC method.
+fn ping_response() -> OutputData {
+    OutputData {
+        result: "ok".to_string(),
+        mentat_meta: MentatMeta {
+            tokens_input: None,
+            tokens_output: None,
+            seconds: Some(0.0),
+            model: "{{AGENT_ID}}".to_string(),
+            timed_out: None,
+            sandbox: guard_report().sandbox.clone(),
+        },
+    }
+}
+
+/// Dispatch a JSON-RPC method name to its handler.
+///
+/// Unknown methods are rejected with the standard `-32601` code rather than
+/// panicking, so one bad call can't take down a process serving many
+/// addressable requests.
+fn dispatch_rpc_method(method: &str, params: InputData) -> Result<OutputData, RpcError> {
+    match method {
+        "process" => guarded_process(params).map_err(|error_response| RpcError {
+            code: -32000,
+            message: error_response.error,
+        }),
+        "ping" => Ok(ping_response()),
+        other => Err(RpcError {
+            code: -32601,
+            message: format!("Method not found: {}", other),
+        }),
+    }
+}
+
+/// Handle one line of JSON-RPC input, always returning a response so the
+/// caller can echo the incoming `id` back, even on parse failure.
+fn handle_rpc_line(line: &str) -> JsonRpcResponse {
+    let raw: serde_json::Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("JSON-RPC parse error: {}", e);
+            return JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                result: None,
+                error: Some(RpcError {
+                    code: -32700,
+                    message: "Parse error".to_string(),
+                }),
+                id: serde_json::Value::Null,
+            };
+        }
+    };
+    let id = raw.get("id").cloned().unwrap_or(serde_json::Value::Null);
+
+    // The JSON itself parsed fine above; a failure here means the envelope
+    // doesn't match the JSON-RPC request shape, which is Invalid Request
+    // (-32600), not a parse error (-32700, already handled above).
+    let request: JsonRpcRequest = match serde_json::from_value(raw) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("JSON-RPC request malformed: {}", e);
+            return JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                result: None,
+                error: Some(RpcError {
+                    code: -32600,
+                    message: "Invalid Request".to_string(),
+                }),
+                id,
+            };
+        }
+    };
+    let id = request.id.clone();
+
+    match dispatch_rpc_method(&request.method, request.params) {
+        Ok(output) => JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            result: Some(output),
+            error: None,
+            id,
+        },
+        Err(rpc_error) => JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            result: None,
+            error: Some(rpc_error),
+            id,
+        },
+    }
+}
+
+/// Run the agent as a JSON-RPC 2.0 server: one envelope per stdin line, one
+/// response envelope per stdout line, until EOF.
+fn run_rpc_loop() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_rpc_line(line.trim());
+        writeln!(stdout, "{}", serde_json::to_string(&response).unwrap())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Run the agent as a persistent loop: one `InputData` per stdin line, one
+/// `OutputData`/`ErrorResponse` per stdout line, until EOF.
+///
+/// A malformed line reports an `ErrorResponse` and continues; only EOF ends
+/// the loop, so a supervising orchestrator can keep one warm process around
+/// for many requests instead of paying startup cost per call.
+fn run_loop() -> io::Result<()> {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        handle_request_line(line.trim())?;
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    init_execution_guards();
+
+    if rpc_mode_requested() {
+        return run_rpc_loop();
+    }
+
+    if framed_mode_requested() {
+        return run_framed_loop();
+    }
+
+    if streaming_mode_requested() {
+        return run_streaming();
+    }
+
+    if loop_mode_requested() {
+        return run_loop();
+    }
+
+    // Read JSON input from stdin
+    let mut input_buffer = String::new();
+    io::stdin().read_to_string(&mut input_buffer)?;
+
+    if input_buffer.trim().is_empty() {
+        let error_response = create_error_response("No input received from stdin".to_string());
+        let json_output = serde_json::to_string(&error_response).unwrap();
+        print!("{}", json_output);
+        io::stdout().flush()?;
+        std::process::exit(1);
+    }
+
+    if !handle_request_line(input_buffer.trim())? {
+        std::process::exit(1);
+    }
+
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn read_framed_message_parses_header_and_body() {
+        let mut reader = BufReader::new("Content-Length: 5\r\n\r\nhello".as_bytes());
+        let message = read_framed_message(&mut reader).unwrap();
+        match message {
+            Some(FramedRead::Message(body)) => assert_eq!(body, "hello"),
+            other => panic!("expected a decoded message, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn read_framed_message_is_case_insensitive_and_ignores_other_headers() {
+        let mut reader =
+            BufReader::new("X-Ignored: yes\r\ncontent-LENGTH: 2\r\n\r\nhi".as_bytes());
+        let message = read_framed_message(&mut reader).unwrap();
+        match message {
+            Some(FramedRead::Message(body)) => assert_eq!(body, "hi"),
+            other => panic!("expected a decoded message, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn read_framed_message_returns_none_at_clean_eof() {
+        let mut reader = BufReader::new("".as_bytes());
+        assert!(read_framed_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_framed_message_resyncs_on_missing_content_length() {
+        let mut reader =
+            BufReader::new("X-Ignored: yes\r\n\r\nContent-Length: 2\r\n\r\nok".as_bytes());
+        match read_framed_message(&mut reader).unwrap() {
+            Some(FramedRead::Error(_)) => {}
+            other => panic!("expected a framing error, got {:?}", other.is_some()),
+        }
+        match read_framed_message(&mut reader).unwrap() {
+            Some(FramedRead::Message(body)) => assert_eq!(body, "ok"),
+            other => panic!("expected the next frame after resync, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn read_framed_message_resyncs_on_truncated_body() {
+        // Declares 10 body bytes but the stream only has 3 before EOF.
+        let mut reader = BufReader::new("Content-Length: 10\r\n\r\nhi!".as_bytes());
+        match read_framed_message(&mut reader).unwrap() {
+            Some(FramedRead::Error(_)) => {}
+            other => panic!("expected a recoverable framing error, got {:?}", other.is_some()),
+        }
+        // The stream then ends cleanly rather than failing the next read.
+        assert!(read_framed_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn handle_rpc_line_echoes_id_on_success() {
+        let response = handle_rpc_line(r#"{"jsonrpc":"2.0","method":"ping","id":7}"#);
+        assert_eq!(response.id, serde_json::json!(7));
+        assert!(response.result.is_some());
+        assert!(response.error.is_none());
+    }
+
+    #[test]
+    fn handle_rpc_line_reports_parse_error_for_invalid_json() {
+        let response = handle_rpc_line("not json");
+        assert_eq!(response.id, serde_json::Value::Null);
+        assert_eq!(response.error.unwrap().code, -32700);
+    }
+
+    #[test]
+    fn handle_rpc_line_reports_invalid_request_for_wrong_shape() {
+        // Valid JSON, but missing the required `method`/`id` fields.
+        let response = handle_rpc_line(r#"{"jsonrpc":"2.0"}"#);
+        assert_eq!(response.error.unwrap().code, -32600);
+    }
+
+    #[test]
+    fn handle_rpc_line_reports_method_not_found() {
+        let response = handle_rpc_line(r#"{"jsonrpc":"2.0","method":"bogus","id":1}"#);
+        assert_eq!(response.id, serde_json::json!(1));
+        assert_eq!(response.error.unwrap().code, -32601);
+    }
+
+    #[test]
+    fn agent_event_chunk_serializes_with_type_tag() {
+        let event = AgentEvent::Chunk { delta: "hi".to_string() };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "chunk");
+        assert_eq!(json["delta"], "hi");
+    }
+
+    #[test]
+    fn agent_event_result_and_error_serialize_with_type_tag() {
+        let output = OutputData {
+            result: "done".to_string(),
+            mentat_meta: MentatMeta {
+                tokens_input: Some(1),
+                tokens_output: Some(1),
+                seconds: Some(0.0),
+                model: "{{AGENT_ID}}".to_string(),
+                timed_out: None,
+                sandbox: None,
+            },
+        };
+        let json = serde_json::to_value(AgentEvent::Result(output)).unwrap();
+        assert_eq!(json["type"], "result");
+        assert_eq!(json["result"], "done");
+
+        let error_event = AgentEvent::Error(create_error_response("boom".to_string()));
+        let json = serde_json::to_value(&error_event).unwrap();
+        assert_eq!(json["type"], "error");
+        assert_eq!(json["error"], "boom");
+    }
 }
\ No newline at end of file